@@ -1,7 +1,24 @@
 use super::{datagram::TunDatagram, netstack};
-use std::{net::SocketAddr, process::Command, sync::Arc};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    process::Command,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use futures::{SinkExt, StreamExt};
+use hickory_proto::op::Message;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Mutex,
+    time::{Instant, Sleep},
+};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 use tun::{Device, TunPacket};
 use url::Url;
@@ -15,13 +32,435 @@ use crate::{
     Error, Runner,
 };
 
+/// Length of the `struct tun_pi` packet-information header some tun devices
+/// prepend to every frame.
+const PI_HEADER_LEN: usize = 4;
+
+/// Builds the 4-byte packet-information header for an outbound IP `payload`,
+/// selecting the ethertype from the IP version nibble.
+fn pi_header(payload: &[u8]) -> [u8; PI_HEADER_LEN] {
+    // flags are unused; proto is the big-endian ethertype
+    let proto: u16 = match payload.first().map(|b| b >> 4) {
+        Some(6) => 0x86dd, // ETH_P_IPV6
+        _ => 0x0800,       // ETH_P_IP
+    };
+    let [hi, lo] = proto.to_be_bytes();
+    [0, 0, hi, lo]
+}
+
+/// A parsed `dns_hijack` target. `any:53` hijacks every query to the given
+/// port regardless of destination, while a concrete socket address only
+/// hijacks queries sent to that server.
+#[derive(Clone, Copy, Debug)]
+enum DnsHijack {
+    Any(u16),
+    Addr(SocketAddr),
+}
+
+impl DnsHijack {
+    fn parse(s: &str) -> Result<Self, Error> {
+        if let Some(port) = s.strip_prefix("any:") {
+            let port = port
+                .parse()
+                .map_err(|_| Error::InvalidConfig(format!("invalid dns_hijack {}", s)))?;
+            Ok(DnsHijack::Any(port))
+        } else {
+            let addr = s
+                .parse()
+                .map_err(|_| Error::InvalidConfig(format!("invalid dns_hijack {}", s)))?;
+            Ok(DnsHijack::Addr(addr))
+        }
+    }
+
+    fn matches(&self, dst: &SocketAddr) -> bool {
+        match self {
+            DnsHijack::Any(port) => dst.port() == *port,
+            DnsHijack::Addr(addr) => addr == dst,
+        }
+    }
+}
+
+fn should_hijack(hijack: &[DnsHijack], dst: &SocketAddr) -> bool {
+    hijack.iter().any(|h| h.matches(dst))
+}
+
+/// Runs a system command for route/DNS provisioning, logging the outcome.
+/// Failures are only warned about so a partially-applied setup can still be
+/// rolled back by the recorded teardown commands.
+fn run_system_command(args: &[&str]) {
+    debug!("running: {}", args.join(" "));
+    match Command::new(args[0]).args(&args[1..]).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("command `{}` exited with {}", args.join(" "), status),
+        Err(e) => warn!("failed to run `{}`: {}", args.join(" "), e),
+    }
+}
+
+/// Assigns an IPv6 address to the tun device. The `tun` crate only configures
+/// a single IPv4 address, so dual-stack support is added out-of-band with the
+/// platform's address tool.
+fn assign_ipv6_address(tun_name: &str, addr: std::net::Ipv6Addr, prefix: u8) {
+    let cidr = format!("{}/{}", addr, prefix);
+
+    #[cfg(target_os = "linux")]
+    run_system_command(&["ip", "-6", "addr", "add", &cidr, "dev", tun_name]);
+    #[cfg(target_os = "macos")]
+    run_system_command(&["ifconfig", tun_name, "inet6", "add", &cidr]);
+    #[cfg(target_os = "windows")]
+    run_system_command(&[
+        "netsh", "interface", "ipv6", "add", "address", tun_name, &cidr,
+    ]);
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        let _ = (tun_name, cidr);
+    }
+}
+
+/// Returns the name of the interface carrying the current default route.
+/// `get_runner` logs it when `auto_detect_interface` is set; outbound sockets
+/// are not bound to it.
+fn detect_default_interface() -> Option<String> {
+    #[cfg(target_os = "linux")]
+    let args: &[&str] = &["ip", "route", "show", "default"];
+    #[cfg(target_os = "macos")]
+    let args: &[&str] = &["route", "-n", "get", "default"];
+    #[cfg(target_os = "windows")]
+    let args: &[&str] = &["powershell", "-Command", "(Get-NetRoute -DestinationPrefix 0.0.0.0/0 | Sort-Object RouteMetric | Select-Object -First 1).InterfaceAlias"];
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    let args: &[&str] = &[];
+
+    // no known command to probe the default route on this platform (e.g.
+    // android/ios) - leave interface binding to the caller
+    if args.is_empty() {
+        return None;
+    }
+
+    let out = Command::new(args[0]).args(&args[1..]).output().ok()?;
+    let text = String::from_utf8_lossy(&out.stdout);
+
+    #[cfg(target_os = "linux")]
+    {
+        // "default via <gw> dev <iface> ..."
+        let mut it = text.split_whitespace();
+        while let Some(tok) = it.next() {
+            if tok == "dev" {
+                return it.next().map(|s| s.to_owned());
+            }
+        }
+        None
+    }
+    #[cfg(target_os = "macos")]
+    {
+        text.lines()
+            .find_map(|l| l.trim().strip_prefix("interface: "))
+            .map(|s| s.trim().to_owned())
+    }
+    #[cfg(target_os = "windows")]
+    {
+        let iface = text.trim();
+        (!iface.is_empty()).then(|| iface.to_owned())
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        let _ = text;
+        None
+    }
+}
+
+/// Dedicated policy-routing table the tun default routes live in, so the
+/// host's existing default route is left untouched and can be restored.
+#[cfg(target_os = "linux")]
+const TUN_ROUTE_TABLE: &str = "2468";
+
+/// Priority of the `ip rule` that directs traffic into [`TUN_ROUTE_TABLE`].
+#[cfg(target_os = "linux")]
+const TUN_RULE_PREF: &str = "9000";
+
+/// Installs the platform-specific routes that point default traffic at the tun
+/// device, recording the inverse of every applied command so the system
+/// routing table can be restored on teardown. DNS is only pointed at the tun
+/// on Linux (through `resolvectl`); on Windows only IPv4 routes are installed.
+struct AutoRoute {
+    teardown: Vec<Vec<String>>,
+}
+
+impl AutoRoute {
+    fn install(tun_name: &str, tun_addr: &std::net::IpAddr, ipv6: bool) -> Self {
+        let mut this = AutoRoute { teardown: vec![] };
+        let addr = tun_addr.to_string();
+        let _ = (&addr, ipv6);
+
+        #[cfg(target_os = "linux")]
+        {
+            // use a dedicated routing table plus an `ip rule` instead of a bare
+            // `ip route add default`, which would fail with EEXIST on any host
+            // that already has a default route. `route replace` is idempotent
+            // within our own (otherwise empty) table.
+            this.apply(
+                &[
+                    "ip", "route", "replace", "default", "dev", tun_name, "table", TUN_ROUTE_TABLE,
+                ],
+                &[
+                    "ip", "route", "del", "default", "dev", tun_name, "table", TUN_ROUTE_TABLE,
+                ],
+            );
+            this.apply(
+                &[
+                    "ip", "rule", "add", "from", "all", "lookup", TUN_ROUTE_TABLE, "pref",
+                    TUN_RULE_PREF,
+                ],
+                &[
+                    "ip", "rule", "del", "from", "all", "lookup", TUN_ROUTE_TABLE, "pref",
+                    TUN_RULE_PREF,
+                ],
+            );
+            if ipv6 {
+                this.apply(
+                    &[
+                        "ip", "-6", "route", "replace", "default", "dev", tun_name, "table",
+                        TUN_ROUTE_TABLE,
+                    ],
+                    &[
+                        "ip", "-6", "route", "del", "default", "dev", tun_name, "table",
+                        TUN_ROUTE_TABLE,
+                    ],
+                );
+                this.apply(
+                    &[
+                        "ip", "-6", "rule", "add", "from", "all", "lookup", TUN_ROUTE_TABLE,
+                        "pref", TUN_RULE_PREF,
+                    ],
+                    &[
+                        "ip", "-6", "rule", "del", "from", "all", "lookup", TUN_ROUTE_TABLE,
+                        "pref", TUN_RULE_PREF,
+                    ],
+                );
+            }
+            this.apply(
+                &["resolvectl", "dns", tun_name, &addr],
+                &["resolvectl", "revert", tun_name],
+            );
+        }
+        #[cfg(target_os = "macos")]
+        {
+            this.apply(
+                &["route", "add", "-net", "0.0.0.0/1", "-interface", tun_name],
+                &["route", "delete", "-net", "0.0.0.0/1", "-interface", tun_name],
+            );
+            this.apply(
+                &["route", "add", "-net", "128.0.0.0/1", "-interface", tun_name],
+                &["route", "delete", "-net", "128.0.0.0/1", "-interface", tun_name],
+            );
+            if ipv6 {
+                this.apply(
+                    &["route", "add", "-inet6", "-net", "::/0", "-interface", tun_name],
+                    &["route", "delete", "-inet6", "-net", "::/0", "-interface", tun_name],
+                );
+            }
+        }
+        #[cfg(target_os = "windows")]
+        {
+            this.apply(
+                &[
+                    "netsh", "interface", "ipv4", "set", "interface", tun_name, "metric=1",
+                ],
+                &[
+                    "netsh", "interface", "ipv4", "set", "interface", tun_name, "metric=auto",
+                ],
+            );
+            this.apply(
+                &[
+                    "netsh", "interface", "ipv4", "add", "route", "0.0.0.0/0", tun_name, &addr,
+                ],
+                &[
+                    "netsh", "interface", "ipv4", "delete", "route", "0.0.0.0/0", tun_name,
+                ],
+            );
+        }
+
+        this
+    }
+
+    /// Applies `cmd` now and remembers `undo` to be run on teardown.
+    fn apply(&mut self, cmd: &[&str], undo: &[&str]) {
+        run_system_command(cmd);
+        self.teardown
+            .push(undo.iter().map(|s| s.to_string()).collect());
+    }
+
+    /// Runs the recorded inverse commands in reverse order of application.
+    fn teardown(self) {
+        for cmd in self.teardown.into_iter().rev() {
+            run_system_command(&cmd.iter().map(String::as_str).collect::<Vec<_>>());
+        }
+    }
+}
+
+/// Serves DNS-over-TCP on a hijacked stream, framing each message with the
+/// 2-byte length prefix mandated by RFC 1035 until the client hangs up.
+async fn hijack_dns_stream(stream: &mut netstack::TcpStream, resolver: &ThreadSafeDNSResolver) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    loop {
+        let len = match stream.read_u16().await {
+            Ok(len) => len as usize,
+            Err(_) => return,
+        };
+
+        let mut buf = vec![0u8; len];
+        if stream.read_exact(&mut buf).await.is_err() {
+            return;
+        }
+
+        let resp = match hijack_dns(resolver, &buf).await {
+            Some(resp) => resp,
+            None => return,
+        };
+
+        if stream.write_u16(resp.len() as u16).await.is_err()
+            || stream.write_all(&resp).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Answers a DNS query directly from the resolver instead of dispatching it
+/// through a proxy. Returns the wire-format response on success. A/AAAA
+/// queries are served by the resolver's fake-ip pool when enabled; other
+/// record types fall through to real resolution inside `exchange`.
+async fn hijack_dns(resolver: &ThreadSafeDNSResolver, data: &[u8]) -> Option<Vec<u8>> {
+    let query = match Message::from_vec(data) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!("failed to parse hijacked dns query: {}", e);
+            return None;
+        }
+    };
+
+    match resolver.exchange(&query).await {
+        Ok(resp) => match resp.to_vec() {
+            Ok(buf) => Some(buf),
+            Err(e) => {
+                warn!("failed to serialize dns response: {}", e);
+                None
+            }
+        },
+        Err(e) => {
+            debug!("failed to resolve hijacked dns query: {}", e);
+            None
+        }
+    }
+}
+
+/// Wraps a stream so a read or write that makes no progress for `timeout`
+/// fails with [`io::ErrorKind::TimedOut`]. The deadline is reset on every
+/// byte moved in either direction, giving a true idle (not total) timeout.
+struct IdleTimeoutStream<S> {
+    inner: S,
+    timeout: Duration,
+    idle: Pin<Box<Sleep>>,
+}
+
+impl<S> IdleTimeoutStream<S> {
+    fn new(inner: S, timeout: Duration) -> Self {
+        Self {
+            inner,
+            timeout,
+            idle: Box::pin(tokio::time::sleep(timeout)),
+        }
+    }
+
+    fn reset(&mut self) {
+        let deadline = Instant::now() + self.timeout;
+        self.idle.as_mut().reset(deadline);
+    }
+
+    fn expired(&mut self, cx: &mut Context<'_>) -> bool {
+        self.idle.as_mut().poll(cx).is_ready()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeoutStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                if buf.filled().len() != before {
+                    self.reset();
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(err) => Poll::Ready(err),
+            Poll::Pending if self.expired(cx) => {
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout")))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeoutStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(n)) => {
+                self.reset();
+                Poll::Ready(Ok(n))
+            }
+            Poll::Ready(err) => Poll::Ready(err),
+            Poll::Pending if self.expired(cx) => {
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout")))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// A single live UDP flow tracked in the NAT table. Holds the sender used to
+/// forward packets to its dedicated dispatcher datagram, the handle of the
+/// reply task pumping packets back to the stack, and the deadline after which
+/// the sweeper reaps the flow.
+struct UdpNatEntry {
+    d_tx: tokio::sync::mpsc::Sender<UdpPacket>,
+    reply_task: tokio::task::JoinHandle<()>,
+    deadline: Instant,
+}
+
+/// UDP NAT table keyed by `(source, destination)` so every flow gets its own
+/// [`Session`] and [`TunDatagram`].
+type UdpNat = HashMap<(SocketAddr, SocketAddr), UdpNatEntry>;
+
 async fn handl_inbound_stream(
-    stream: netstack::TcpStream,
+    mut stream: netstack::TcpStream,
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
     dispatcher: Arc<Dispatcher>,
     resolver: ThreadSafeDNSResolver,
+    dns_hijack: Arc<Vec<DnsHijack>>,
+    tcp_timeout: Duration,
 ) {
+    // DNS-over-TCP queries to a hijacked server are answered locally
+    if should_hijack(&dns_hijack, &remote_addr) {
+        hijack_dns_stream(&mut stream, &resolver).await;
+        return;
+    }
+
     let mut sess = Session {
         network: Network::Tcp,
         source: local_addr,
@@ -38,79 +477,172 @@ async fn handl_inbound_stream(
         }
     }
 
-    dispatcher.dispatch_stream(sess, stream).await;
+    dispatcher
+        .dispatch_stream(sess, IdleTimeoutStream::new(stream, tcp_timeout))
+        .await;
 }
 
 async fn handle_inbound_datagram(
     socket: Box<netstack::UdpSocket>,
     dispatcher: Arc<Dispatcher>,
     resolver: ThreadSafeDNSResolver,
+    dns_hijack: Arc<Vec<DnsHijack>>,
+    udp_timeout: Duration,
+    sd: CancellationToken,
 ) {
-    // netstack communications
+    // netstack communications - `ls` is shared by every flow's reply task to
+    // push packets back into the stack
     let (ls, mut lr) = socket.split();
     let ls = Arc::new(ls);
 
-    let (l_tx, mut l_rx) = tokio::sync::mpsc::channel::<UdpPacket>(32);
-
-    let (d_tx, mut d_rx) = tokio::sync::mpsc::channel::<UdpPacket>(32);
-
-    // for dispatcher - the dispatcher would receive packets from this channel, which is from the stack
-    // and send back packets to this channel, which is to the tun
-    let udp_stream = TunDatagram::new(l_tx, d_rx);
-
-    tokio::spawn(async move {
-        while let Some(pkt) = l_rx.recv().await {
-            let src_addr = match pkt.src_addr {
-                SocksAddr::Ip(ip) => ip,
-                SocksAddr::Domain(host, port) => {
-                    if let Some(ip) = resolver.lookup_fake_ip(&host).await {
-                        (ip, port).into()
-                    } else {
-                        warn!("failed to resolve fake ip: {}", host);
-                        continue;
+    let nat: Arc<Mutex<UdpNat>> = Arc::new(Mutex::new(UdpNat::new()));
+
+    // sweeper: reap flows that have been idle past `udp_timeout`. Dropping an
+    // entry drops its `d_tx`, which closes the dispatcher datagram once the
+    // last sender is gone. It selects on the shutdown token so it cannot
+    // outlive the handler (and keep the whole NAT map alive) after a stop().
+    let sweeper = {
+        let nat = nat.clone();
+        let sd = sd.clone();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(udp_timeout);
+            loop {
+                tokio::select! {
+                    _ = sd.cancelled() => break,
+                    _ = tick.tick() => {
+                        let now = Instant::now();
+                        nat.lock().await.retain(|_, e| {
+                            let keep = e.deadline > now;
+                            // dropping the entry closes its dispatcher datagram;
+                            // also abort the reply task so it isn't orphaned
+                            if !keep {
+                                e.reply_task.abort();
+                            }
+                            keep
+                        });
                     }
                 }
-            };
-            if let Err(e) = ls.send_to(
-                &pkt.data[..],
-                &src_addr,
-                &pkt.dst_addr.must_into_socket_addr(),
-            ) {
-                warn!("failed to send udp packet to netstack: {}", e);
             }
-        }
-    });
+        })
+    };
 
-    tokio::spawn(async move {
-        // TODO: handle DNS
-        while let Ok((data, src_addr, dst_addr)) = lr.recv_from().await {
-            let pkt = UdpPacket {
-                data,
-                src_addr: src_addr.into(),
-                dst_addr: dst_addr.into(),
-            };
-
-            match d_tx.send(pkt).await {
-                Ok(_) => {}
-                Err(e) => {
-                    warn!("failed to send udp packet to proxy: {}", e);
+    loop {
+        let (data, src_addr, dst_addr) = tokio::select! {
+            _ = sd.cancelled() => break,
+            res = lr.recv_from() => match res {
+                Ok(v) => v,
+                Err(_) => break,
+            },
+        };
+
+        // answer DNS queries locally instead of dispatching them through a
+        // proxy when the destination matches the `dns_hijack` list
+        if should_hijack(&dns_hijack, &dst_addr) {
+            if let Some(resp) = hijack_dns(&resolver, &data).await {
+                if let Err(e) = ls.send_to(&resp[..], &dst_addr, &src_addr) {
+                    warn!("failed to send hijacked dns response: {}", e);
                 }
             }
+            // a destination we chose to hijack is never proxied out, even when
+            // the local answer failed - drop it rather than leaking the query
+            continue;
         }
-    });
 
-    let sess = Session {
-        network: Network::Udp,
-        ..Default::default()
-    };
+        let key = (src_addr, dst_addr);
+        let deadline = Instant::now() + udp_timeout;
+
+        let mut guard = nat.lock().await;
+        let d_tx = match guard.get_mut(&key) {
+            Some(entry) => {
+                entry.deadline = deadline;
+                entry.d_tx.clone()
+            }
+            None => {
+                // a new flow gets its own channels, `Session` and datagram
+                let (l_tx, mut l_rx) = tokio::sync::mpsc::channel::<UdpPacket>(32);
+                let (d_tx, d_rx) = tokio::sync::mpsc::channel::<UdpPacket>(32);
+                let udp_stream = TunDatagram::new(l_tx, d_rx);
+
+                let sess = Session {
+                    network: Network::Udp,
+                    source: src_addr,
+                    destination: dst_addr.into(),
+                    ..Default::default()
+                };
+
+                // pump replies from the proxy back into the netstack
+                let ls = ls.clone();
+                let resolver = resolver.clone();
+                let reply_nat = nat.clone();
+                let reply_task = tokio::spawn(async move {
+                    while let Some(pkt) = l_rx.recv().await {
+                        // a server reply is flow activity too, so refresh the
+                        // idle deadline - otherwise a sparse-uplink transfer
+                        // could be reaped mid-response
+                        if let Some(entry) = reply_nat.lock().await.get_mut(&key) {
+                            entry.deadline = Instant::now() + udp_timeout;
+                        }
+
+                        let src_addr = match pkt.src_addr {
+                            SocksAddr::Ip(ip) => ip,
+                            SocksAddr::Domain(host, port) => {
+                                if let Some(ip) = resolver.lookup_fake_ip(&host).await {
+                                    (ip, port).into()
+                                } else {
+                                    warn!("failed to resolve fake ip: {}", host);
+                                    continue;
+                                }
+                            }
+                        };
+                        if let Err(e) = ls.send_to(
+                            &pkt.data[..],
+                            &src_addr,
+                            &pkt.dst_addr.must_into_socket_addr(),
+                        ) {
+                            warn!("failed to send udp packet to netstack: {}", e);
+                        }
+                    }
+                });
+
+                dispatcher.dispatch_datagram(sess, Box::new(udp_stream));
+
+                guard.insert(
+                    key,
+                    UdpNatEntry {
+                        d_tx: d_tx.clone(),
+                        reply_task,
+                        deadline,
+                    },
+                );
+                d_tx
+            }
+        };
+        drop(guard);
+
+        let pkt = UdpPacket {
+            data,
+            src_addr: src_addr.into(),
+            dst_addr: dst_addr.into(),
+        };
+        if let Err(e) = d_tx.send(pkt).await {
+            warn!("failed to send udp packet to proxy: {}", e);
+        }
+    }
 
-    dispatcher.dispatch_datagram(sess, Box::new(udp_stream));
+    // shutdown: stop the sweeper and tear down every live flow, dropping the
+    // dispatcher datagrams and aborting the reply tasks so nothing is leaked
+    // across a hot-reload
+    sweeper.abort();
+    for (_, entry) in nat.lock().await.drain() {
+        entry.reply_task.abort();
+    }
 }
 
 pub fn get_runner(
     cfg: TunConfig,
     dispatcher: Arc<Dispatcher>,
     resolver: ThreadSafeDNSResolver,
+    sd: CancellationToken,
 ) -> Result<Option<Runner>, Error> {
     if !cfg.enable {
         return Ok(None);
@@ -118,6 +650,16 @@ pub fn get_runner(
 
     let device_id = cfg.device_id;
 
+    let dns_hijack = Arc::new(
+        cfg.dns_hijack
+            .iter()
+            .map(|s| DnsHijack::parse(s))
+            .collect::<Result<Vec<_>, _>>()?,
+    );
+
+    let udp_timeout = Duration::from_secs(cfg.udp_timeout.unwrap_or(10));
+    let tcp_timeout = Duration::from_secs(cfg.tcp_timeout.unwrap_or(60));
+
     let u =
         Url::parse(&device_id).map_err(|x| Error::InvalidConfig(format!("tun device {}", x)))?;
 
@@ -151,60 +693,151 @@ pub fn get_runner(
         .unwrap_or(&"198.18.0.0/16".to_owned())
         .parse::<ipnet::IpNet>()?;
 
+    let tun_addr = network.hosts().nth(0).expect(
+        format!("tun network {:?} doesn't contain any address", cfg.network).as_str(),
+    );
+
+    // optional IPv6 ULA network (e.g. fdfe:dcba:9876::/64); the `tun` crate
+    // only models a single v4 address, so the v6 address is assigned with a
+    // platform command once the device is up
+    let ipv6_network = cfg
+        .ipv6_network
+        .as_ref()
+        .map(|s| s.parse::<ipnet::Ipv6Net>())
+        .transpose()
+        .map_err(|x| Error::InvalidConfig(format!("tun ipv6 network {}", x)))?;
+    let ipv6_addr = ipv6_network.and_then(|n| {
+        n.hosts()
+            .nth(0)
+            .map(|addr| (addr, n.prefix_len()))
+    });
+
+    let mtu = cfg.mtu.unwrap_or(1500);
+
     tun_cfg
-        .address(
-            network.hosts().nth(0).expect(
-                format!("tun network {:?} doesn't contain any address", cfg.network).as_str(),
-            ),
-        )
+        .address(tun_addr)
         .netmask(network.netmask())
+        .mtu(mtu as i32)
         .up();
 
+    // We handle the 4-byte packet-information header ourselves via `pi_offset`,
+    // so keep the `tun` crate's framing codec out of it: without this, a
+    // platform whose `into_framed()` already strips/adds PI would double-process
+    // and our manual offset would lop 4 bytes off the real IP header. Disabling
+    // the crate's PI handling makes `pi_offset` the single source of truth.
+    #[cfg(unix)]
+    tun_cfg.platform(|platform| {
+        platform.packet_information(false);
+    });
+
+    // offset computed once here, not per packet
+    let pi_offset = if cfg.packet_information {
+        PI_HEADER_LEN
+    } else {
+        0
+    };
+
     let tun = tun::create_as_async(&tun_cfg).map_err(map_io_error)?;
 
     let tun_name = tun.get_ref().name().to_owned();
     info!("tun started at {}", tun_name);
 
-    let (stack, mut tcp_listener, udp_socket) =
-        netstack::NetStack::with_buffer_size(512, 256).map_err(map_io_error)?;
+    if let Some((addr, prefix)) = ipv6_addr {
+        assign_ipv6_address(&tun_name, addr, prefix);
+    }
+
+    let auto_route = cfg.auto_route;
+
+    // when requested, detect the current default interface and log its name so
+    // operators can see what the tun is taking over from
+    if cfg.auto_detect_interface {
+        match detect_default_interface() {
+            Some(iface) => info!("tun auto-detected default interface: {}", iface),
+            None => warn!("tun auto_detect_interface: no default interface found"),
+        }
+    }
+
+    let (stack, mut tcp_listener, udp_socket) = netstack::NetStack::with_buffer_size(
+        cfg.tcp_buffer.unwrap_or(512),
+        cfg.udp_buffer.unwrap_or(256),
+    )
+    .map_err(map_io_error)?;
 
     Ok(Some(Box::pin(async move {
+        // install system routes/DNS once the device is up, to be reverted
+        // after the packet pumps stop
+        let routing = if auto_route {
+            Some(AutoRoute::install(&tun_name, &tun_addr, ipv6_addr.is_some()))
+        } else {
+            None
+        };
+
         let framed = tun.into_framed();
 
         let (mut tun_sink, mut tun_stream) = framed.split();
         let (mut stack_sink, mut stack_stream) = stack.split();
 
-        let mut futs: Vec<Runner> = vec![];
-
-        futs.push(Box::pin(async move {
-            while let Some(pkt) = stack_stream.next().await {
-                match pkt {
-                    Ok(pkt) => {
-                        if let Err(e) = tun_sink.send(TunPacket::new(pkt)).await {
-                            error!("failed to send pkt to tun: {}", e);
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        error!("tun stack error: {}", e);
-                        break;
+        // store the spawned tasks so a single `stop()` (via the shutdown token)
+        // reliably aborts every pump, the accept loop and the UDP NAT tasks
+        let mut handles: Vec<tokio::task::JoinHandle<()>> = vec![];
+
+        handles.push(tokio::spawn({
+            let sd = sd.clone();
+            async move {
+                loop {
+                    tokio::select! {
+                        _ = sd.cancelled() => break,
+                        pkt = stack_stream.next() => match pkt {
+                            Some(Ok(pkt)) => {
+                                let frame = if pi_offset != 0 {
+                                    let mut buf = Vec::with_capacity(PI_HEADER_LEN + pkt.len());
+                                    buf.extend_from_slice(&pi_header(&pkt));
+                                    buf.extend_from_slice(&pkt);
+                                    buf
+                                } else {
+                                    pkt
+                                };
+                                if let Err(e) = tun_sink.send(TunPacket::new(frame)).await {
+                                    error!("failed to send pkt to tun: {}", e);
+                                    break;
+                                }
+                            }
+                            Some(Err(e)) => {
+                                error!("tun stack error: {}", e);
+                                break;
+                            }
+                            None => break,
+                        },
                     }
                 }
             }
         }));
 
-        futs.push(Box::pin(async move {
-            while let Some(pkt) = tun_stream.next().await {
-                match pkt {
-                    Ok(pkt) => {
-                        if let Err(e) = stack_sink.send(pkt.into_bytes().into()).await {
-                            error!("failed to send pkt to stack: {}", e);
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        error!("tun stream error: {}", e);
-                        break;
+        handles.push(tokio::spawn({
+            let sd = sd.clone();
+            async move {
+                loop {
+                    tokio::select! {
+                        _ = sd.cancelled() => break,
+                        pkt = tun_stream.next() => match pkt {
+                            Some(Ok(pkt)) => {
+                                let bytes = pkt.into_bytes();
+                                // a runt frame shorter than the PI header can't
+                                // carry a packet; dropping it avoids a slice panic
+                                if bytes.len() < pi_offset {
+                                    continue;
+                                }
+                                if let Err(e) = stack_sink.send(bytes.slice(pi_offset..)).await {
+                                    error!("failed to send pkt to stack: {}", e);
+                                    break;
+                                }
+                            }
+                            Some(Err(e)) => {
+                                error!("tun stream error: {}", e);
+                                break;
+                            }
+                            None => break,
+                        },
                     }
                 }
             }
@@ -212,23 +845,63 @@ pub fn get_runner(
 
         let dsp = dispatcher.clone();
         let rsv = resolver.clone();
-        futs.push(Box::pin(async move {
-            while let Some((stream, local_addr, remote_addr)) = tcp_listener.next().await {
-                tokio::spawn(handl_inbound_stream(
-                    stream,
-                    local_addr,
-                    remote_addr,
-                    dsp.clone(),
-                    rsv.clone(),
-                ));
+        let hijack = dns_hijack.clone();
+        handles.push(tokio::spawn({
+            let sd = sd.clone();
+            async move {
+                loop {
+                    tokio::select! {
+                        _ = sd.cancelled() => break,
+                        accepted = tcp_listener.next() => match accepted {
+                            Some((stream, local_addr, remote_addr)) => {
+                                tokio::spawn(handl_inbound_stream(
+                                    stream,
+                                    local_addr,
+                                    remote_addr,
+                                    dsp.clone(),
+                                    rsv.clone(),
+                                    hijack.clone(),
+                                    tcp_timeout,
+                                ));
+                            }
+                            None => break,
+                        },
+                    }
+                }
             }
         }));
 
-        futs.push(Box::pin(async move {
-            handle_inbound_datagram(udp_socket, dispatcher, resolver).await;
+        handles.push(tokio::spawn({
+            let sd = sd.clone();
+            async move {
+                // the handler owns the shutdown token and tears down its
+                // sweeper and per-flow tasks when it is cancelled
+                handle_inbound_datagram(
+                    udp_socket,
+                    dispatcher,
+                    resolver,
+                    dns_hijack,
+                    udp_timeout,
+                    sd,
+                )
+                .await;
+            }
         }));
 
-        futures::future::join_all(futs).await;
+        // stop as soon as *any* pump finishes - whether that's an external
+        // stop() (every task selects on the token and breaks) or one inner task
+        // erroring out. A single fatal error then tears the whole runner down
+        // instead of leaving the survivors pumping forever.
+        let (_first, _idx, remaining) = futures::future::select_all(handles).await;
+        sd.cancel();
+        for handle in &remaining {
+            handle.abort();
+        }
+        futures::future::join_all(remaining).await;
+
+        if let Some(routing) = routing {
+            routing.teardown();
+        }
 
         warn!("tun at {} stopped", tun_name);
     })))